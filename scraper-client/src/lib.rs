@@ -0,0 +1,1598 @@
+//! Typed async client for the site Q&A server's HTTP API.
+//!
+//! ```no_run
+//! # async fn demo() -> scraper_client::Result<()> {
+//! use scraper_client::{AskRequest, Client, IndexManyRequest};
+//!
+//! let client = Client::new("http://127.0.0.1:3000");
+//! client
+//!     .index_many(&IndexManyRequest {
+//!         session_id: "admissions".into(),
+//!         urls: vec!["https://example.edu/admissions".into()],
+//!         max_pages: Some(50),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! let resp = client
+//!     .ask(&AskRequest::new("admissions", "When is the application deadline?"))
+//!     .await?;
+//! println!("{}\n{:?}", resp.answer, resp.sources);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests mirror the server's JSON bodies field for field; every optional field
+//! left at its default is omitted, so the server's defaults apply. Responses are
+//! typed the same way, except free-form ones such as GraphQL results, glossaries
+//! and catalogs, which are returned as `serde_json::Value`.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// ================= Errors =================
+#[derive(Debug)]
+pub enum Error {
+    /// the request failed before a response arrived (connect, timeout, body)
+    Http(reqwest::Error),
+    /// the server answered with an error status; `body` is its message
+    Status { status: u16, body: String },
+    /// a success response that isn't the expected JSON
+    Decode(serde_json::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "request failed: {e}"),
+            Error::Status { status, body } => write!(f, "server answered {status}: {body}"),
+            Error::Decode(e) => write!(f, "unexpected response: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Decode(e) => Some(e),
+            Error::Status { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// ================= Requests =================
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexManyRequest {
+    pub session_id: String,
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pages: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope_prefix: Option<String>,
+    /// same_origin (default) | same_domain | allowlist. `same_domain` is a
+    /// last-two-labels heuristic (three under `co.uk`-style suffixes), not a
+    /// public-suffix lookup, so prefer `allowlist` on hosts like `github.io`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pdf_origin: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pdf_max_pages: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pdf_allowed_hosts: Vec<String>,
+    /// `en`, `de`: skip pages detected in any other language
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub languages: Vec<String>,
+    /// index exactly `urls`, without following links
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub urls_only: bool,
+    /// save the job as a replay bundle
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub record: bool,
+    /// extract program/course records from catalog-like pages
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub catalog: bool,
+    /// run each page's JavaScript in a headless browser first
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub render_js: bool,
+    /// interactive | normal | background; default: interactive for a few pages.
+    /// Only lowers the default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<String>,
+}
+
+/// A file for [`Client::upload`].
+#[derive(Debug, Clone)]
+pub struct UploadFile {
+    /// the file name; its extension decides how the server extracts it
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CaptureRequest {
+    pub session_id: String,
+    pub url: String,
+    pub html: String,
+    /// only needed for captures sent from a browser
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConfluenceRequest {
+    pub session_id: String,
+    /// e.g. https://your-team.atlassian.net
+    pub base_url: String,
+    /// Atlassian account email (basic auth with an API token); omit for a bearer PAT
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    pub token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub space_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cql: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pages: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NotionRequest {
+    pub session_id: String,
+    pub token: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub page_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pages: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GDriveRequest {
+    pub session_id: String,
+    /// OAuth access token with a Drive read scope
+    pub token: String,
+    pub folder_id: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub recursive: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImapRequest {
+    pub session_id: String,
+    pub host: String,
+    /// IMAPS port, default 993
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    pub username: String,
+    pub password: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailbox: Option<String>,
+    /// `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetrievalFilters {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub url_prefixes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_url_prefixes: Vec<String>,
+    /// `YYYY-MM-DD`; documents without a known date are kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_before: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AskRequest {
+    pub session_id: String,
+    pub question: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filters: Option<RetrievalFilters>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuzzy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<usize>,
+    /// `chunks` | `documents`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retrieval: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_documents: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_sources: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_chunks_per_source: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_sources: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub exclude_terms: Vec<String>,
+    /// `{ "models": [...], "judge": "..." }`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ensemble: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decompose: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<AskBudget>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChatRequest {
+    /// from an earlier [`ChatResponse`]; `None` starts a new conversation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    #[serde(flatten)]
+    pub ask: AskRequest,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AskBudget {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_latency_ms: Option<u64>,
+    /// `fast` | `balanced` | `thorough`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<String>,
+}
+
+impl AskRequest {
+    pub fn new(session_id: impl Into<String>, question: impl Into<String>) -> Self {
+        AskRequest {
+            session_id: session_id.into(),
+            question: question.into(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SearchRequest {
+    pub session_id: String,
+    /// free words plus `"phrases"`, `+must`, `-not`, `site:` and `url:`
+    pub query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fuzzy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retrieval: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_documents: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_before: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CrawlRules {
+    /// when non-empty, only matching URLs are followed
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// The nested configuration blocks (`chunking`, `pipeline`, `redaction`, `safety`,
+/// `ask`, …) are passed through as JSON, in the shape the README documents.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CreateSessionRequest {
+    /// generated by the server when omitted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embed_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gen_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunking: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pipeline: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redaction: Option<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub languages: Vec<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub stopwords: BTreeMap<String, Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crawl_rules: Option<CrawlRules>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub summaries: bool,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub glossary: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub safety: Option<Value>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub private: bool,
+    /// sessions of one tenant share its fair share of the embedding slots
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// default ask parameters, same fields as [`AskRequest`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ask: Option<Value>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChunksQuery {
+    /// only chunks of this document
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CoverageQuery {
+    /// `indexed` or `skipped`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// e.g. `depth`, `scope`, `error`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CatalogQuery {
+    /// `master`, `bachelor`, `doctorate` or a substring such as `M.Sc.`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub degree: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HistoryQuery {
+    /// every word must occur in the question or answer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Json,
+    Markdown,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WatchRequest {
+    pub urls: Vec<String>,
+    /// default 60
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_minutes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DigestRequest {
+    /// default 24; `0` disables the digest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_hours: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GraphRequest {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_chunks: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClusterRequest {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<usize>,
+    /// ask the generation model for a label per cluster (default true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GenerateQuestionsRequest {
+    pub session_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChatCompletionRequest {
+    /// a session id
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+}
+
+/// ================= Responses =================
+/// Fields a response may gain in later server versions land in `extra`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct IndexResponse {
+    pub ok: bool,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    pub created_at: String,
+    pub source_scope: String,
+    /// crawl details: pages fetched, errors, PDFs, traps, soft 404s, …
+    pub report: Value,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Snippet {
+    pub chunk_id: String,
+    pub text: String,
+    /// [start, end) character offsets of query terms in `text`
+    pub highlights: Vec<(usize, usize)>,
+    pub html: String,
+    pub link: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Citation {
+    pub url: String,
+    /// deep link to the best snippet
+    pub link: String,
+    pub snippets: Vec<Snippet>,
+    pub dead: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AskResponse {
+    pub answer: String,
+    pub sources: Vec<String>,
+    pub source_scores: Vec<f32>,
+    pub citations: Vec<Citation>,
+    pub did_you_mean: Option<String>,
+    pub index_incomplete: bool,
+    pub verified: bool,
+    pub unsupported_claims: Vec<String>,
+    pub unsupported_numbers: Vec<String>,
+    pub sub_questions: Vec<String>,
+    /// another instance held the session; the question is added to the history later
+    pub history_queued: bool,
+    /// `contributions`, `conflicts`, `ensemble`, `budget`, …
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChatResponse {
+    pub conversation_id: String,
+    /// the follow-up as rewritten for retrieval, when it changed
+    pub standalone_question: Option<String>,
+    #[serde(flatten)]
+    pub answer: AskResponse,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchHit {
+    pub chunk_id: String,
+    pub url: String,
+    pub score: f32,
+    pub snippets: Vec<Snippet>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchResponse {
+    /// the query as parsed by the server
+    pub query: Value,
+    pub matched_chunks: usize,
+    pub results: Vec<SearchHit>,
+    pub did_you_mean: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    pub created_at: String,
+    pub memory: MemoryUsage,
+    /// false for a session whose index the server dropped from memory while idle;
+    /// the counts are then zero until it is used again
+    pub in_memory: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionList {
+    pub total_bytes: usize,
+    pub sessions: Vec<SessionSummary>,
+}
+
+/// Estimated heap footprint of a session, in bytes.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct MemoryUsage {
+    /// chunk ids, urls and text
+    pub chunks: usize,
+    /// chunk, sentence and label vectors
+    pub embeddings: usize,
+    /// per-chunk term frequencies
+    pub tf: usize,
+    pub df: usize,
+    /// labels, abbreviations and the rest
+    pub other: usize,
+    /// full index copies held as snapshots
+    pub snapshots: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub embed_model: String,
+    pub gen_model: String,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    pub created_at: String,
+    pub source_scope: String,
+    /// the session's configuration, as set with [`Client::create_session`]
+    pub config: Value,
+    /// PII items masked so far, per kind
+    pub redacted: BTreeMap<String, usize>,
+    /// lowercase acronym -> long form seen in the corpus
+    pub abbreviations: BTreeMap<String, String>,
+    pub labelled_pages: usize,
+    pub summarized_documents: usize,
+    pub flagged_chunks: usize,
+    pub dead_links: usize,
+    pub memory: MemoryUsage,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionStatus {
+    pub session_id: String,
+    /// the job's phase, or `idle` when the session never ran one
+    pub phase: String,
+    /// the last or running index job
+    pub progress: Option<JobProgress>,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct JobProgress {
+    /// `crawling`, `extracting`, `embedding`, `done` or `failed`
+    pub phase: String,
+    pub started_at: String,
+    pub updated_at: String,
+    pub pages_fetched: usize,
+    pub pages_max: usize,
+    /// page last fetched while crawling, or document being embedded
+    pub current_url: Option<String>,
+    /// PDF or file currently being converted
+    pub extracting: Option<String>,
+    pub chunks_done: usize,
+    pub chunks_total: usize,
+    /// moving average of one embedding call
+    pub embed_ms_avg: Option<f64>,
+    pub eta_secs: Option<u64>,
+    pub error: Option<String>,
+    /// `background`, `normal` or `interactive`
+    pub priority: String,
+    /// waiting for an embedding slot
+    pub waiting: bool,
+}
+
+/// Half-open char and UTF-8 byte ranges of a chunk within its extracted document.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct SourceSpan {
+    pub char_start: usize,
+    pub char_end: usize,
+    pub byte_start: usize,
+    pub byte_end: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChunkItem {
+    pub id: String,
+    pub url: String,
+    /// 1-based PDF page the chunk starts on
+    pub page: Option<u32>,
+    pub span: Option<SourceSpan>,
+    /// dates and amounts in canonical form, e.g. `date:07-15`
+    pub values: Vec<String>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChunkList {
+    /// chunks matching the query, before `offset` and `limit`
+    pub total: usize,
+    pub offset: usize,
+    pub items: Vec<ChunkItem>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SessionStats {
+    pub chunks: usize,
+    pub documents: usize,
+    /// distinct analyzed terms
+    pub vocabulary: usize,
+    pub avg_terms_per_chunk: f32,
+    pub chunk_chars: ChunkChars,
+    /// chunks per domain, most first
+    pub domains: Vec<DomainChunks>,
+    pub embeddings: EmbeddingStats,
+    /// terms by document frequency, most first
+    pub df_head: Vec<TermFrequency>,
+    /// distinct lines currently treated as boilerplate
+    pub boilerplate_lines: usize,
+}
+
+/// Chunk lengths in chars; `None` for a session without chunks.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ChunkChars {
+    pub min: Option<usize>,
+    pub median: Option<usize>,
+    pub max: Option<usize>,
+    pub histogram: Vec<LengthBucket>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LengthBucket {
+    pub from: usize,
+    /// `None` for the last, open-ended bucket
+    pub to: Option<usize>,
+    pub chunks: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DomainChunks {
+    pub domain: String,
+    pub chunks: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EmbeddingStats {
+    pub model: String,
+    pub dims: usize,
+    /// chunks without a vector
+    pub missing: usize,
+    pub avg_norm: Option<f32>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TermFrequency {
+    pub term: String,
+    pub df: usize,
+    /// fraction of chunks containing the term
+    pub share: f32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Coverage {
+    pub session_id: String,
+    pub indexed: usize,
+    pub skipped: usize,
+    /// skipped URLs per reason (`scope`, `depth`, `cap`, `error`, …)
+    pub by_reason: BTreeMap<String, usize>,
+    /// one node per origin, then per path segment
+    pub tree: Vec<CoverageNode>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CoverageNode {
+    pub name: String,
+    /// indexed and skipped URLs at or below this node
+    pub indexed: usize,
+    pub skipped: usize,
+    /// set when a URL ends at this node
+    pub url: Option<String>,
+    /// `indexed` or `skipped`, with the URL
+    pub status: Option<String>,
+    pub reason: Option<String>,
+    pub children: Vec<CoverageNode>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub asked_at: String,
+    pub question: String,
+    pub answer: String,
+    pub sources: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HistoryPage {
+    /// entries matching the query, before `offset` and `limit`
+    pub total: usize,
+    pub offset: usize,
+    /// newest first
+    pub items: Vec<HistoryEntry>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+    pub name: String,
+    pub created_at: String,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    /// always false: snapshots are held in the server's memory and lost on restart
+    pub persisted: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RestoreResponse {
+    pub ok: bool,
+    pub session_id: String,
+    pub restored: Snapshot,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SnapshotDiff {
+    pub from: String,
+    /// a snapshot name, or `current`
+    pub to: String,
+    pub urls_added: Vec<String>,
+    pub urls_removed: Vec<String>,
+    pub urls_changed: Vec<String>,
+    pub chunks_added: Vec<String>,
+    pub chunks_removed: Vec<String>,
+    pub chunks_changed: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Watch {
+    pub url: String,
+    pub interval_secs: u64,
+    pub webhook: Option<String>,
+    pub last_checked: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DigestConfig {
+    pub interval_secs: u64,
+    pub webhook: Option<String>,
+    pub last_run: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Digest {
+    pub created_at: String,
+    /// changes detected after this instant are covered
+    pub since: Option<String>,
+    pub summary: String,
+    pub urls: Vec<String>,
+    pub changes: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GraphNode {
+    pub id: String,
+    pub label: String,
+    /// entity type as the model named it, lowercase; `concept` when it gave none
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub chunk_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    pub relation: String,
+    pub chunk_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Graph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+    pub chunks_analyzed: usize,
+    pub batches_failed: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Cluster {
+    pub id: usize,
+    pub label: String,
+    pub size: usize,
+    pub top_terms: Vec<String>,
+    pub representative_urls: Vec<String>,
+    pub chunk_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ScoredText {
+    pub text: String,
+    pub score: f32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DocumentKeywords {
+    pub url: String,
+    pub terms: Vec<ScoredText>,
+    pub phrases: Vec<ScoredText>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Keywords {
+    pub terms: Vec<ScoredText>,
+    pub phrases: Vec<ScoredText>,
+    /// in crawl order
+    pub documents: Vec<DocumentKeywords>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CaptureResponse {
+    pub ok: bool,
+    /// the page's canonical URL
+    pub url: String,
+    pub chars: usize,
+    pub added_chunks: usize,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    /// chunks dropped or flagged per safety category
+    pub screened: BTreeMap<String, usize>,
+    pub boilerplate_lines: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FileStatus {
+    pub file: String,
+    /// `extracted`, `skipped`, `rejected` or `error`
+    pub status: String,
+    pub reason: Option<String>,
+    pub chars: usize,
+    /// pages crawled from a bookmarks export
+    pub urls: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UploadResponse {
+    pub ok: bool,
+    pub files_processed: usize,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    pub screened: BTreeMap<String, usize>,
+    pub boilerplate_lines: usize,
+    pub files: Vec<FileStatus>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ConnectorResponse {
+    pub ok: bool,
+    /// documents the connector fetched
+    pub documents: usize,
+    pub added_chunks: usize,
+    pub chunks: usize,
+    pub pages_indexed: usize,
+    pub screened: BTreeMap<String, usize>,
+    pub boilerplate_lines: usize,
+}
+
+/// ================= Client =================
+#[derive(Debug, Clone)]
+pub struct Client {
+    base: String,
+    http: reqwest::Client,
+}
+
+/// Percent-encodes one path segment (session ids and snapshot names are free text).
+fn seg(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+async fn checked(resp: reqwest::Response) -> Result<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body = resp.text().await.unwrap_or_default();
+    Err(Error::Status {
+        status: status.as_u16(),
+        body,
+    })
+}
+
+async fn json<T: DeserializeOwned>(resp: reqwest::Response) -> Result<T> {
+    let bytes = checked(resp).await?.bytes().await?;
+    serde_json::from_slice(&bytes).map_err(Error::Decode)
+}
+
+impl Client {
+    /// `base_url` is where the server listens, e.g. `http://127.0.0.1:3000`.
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_http_client(base_url, reqwest::Client::new())
+    }
+
+    /// With a preconfigured reqwest client (timeouts, proxies, default headers).
+    pub fn with_http_client(base_url: impl Into<String>, http: reqwest::Client) -> Self {
+        Client {
+            base: base_url.into().trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    fn session_url(&self, session_id: &str, rest: &str) -> String {
+        self.url(&format!("/api/sessions/{}{rest}", seg(session_id)))
+    }
+
+    async fn get<T: DeserializeOwned>(&self, url: String) -> Result<T> {
+        json(self.http.get(url).send().await?).await
+    }
+
+    async fn get_query<T: DeserializeOwned>(&self, url: String, q: &impl Serialize) -> Result<T> {
+        json(self.http.get(url).query(q).send().await?).await
+    }
+
+    async fn post<T: DeserializeOwned>(&self, url: String, body: &impl Serialize) -> Result<T> {
+        json(self.http.post(url).json(body).send().await?).await
+    }
+
+    async fn put<T: DeserializeOwned>(&self, url: String, body: &impl Serialize) -> Result<T> {
+        json(self.http.put(url).json(body).send().await?).await
+    }
+
+    // ----- indexing -----
+
+    /// Crawls (or with `urls_only`, fetches) the URLs into the session.
+    pub async fn index_many(&self, req: &IndexManyRequest) -> Result<IndexResponse> {
+        self.post(self.url("/api/index_many"), req).await
+    }
+
+    /// Uploads files (PDF, DOCX, HTML, text, bookmark exports, …) into the session.
+    pub async fn upload(&self, session_id: &str, files: &[UploadFile]) -> Result<UploadResponse> {
+        self.upload_with_folder(session_id, None, files).await
+    }
+
+    /// [`Client::upload`], crawling only the bookmarks in `bookmark_folder`.
+    pub async fn upload_with_folder(
+        &self,
+        session_id: &str,
+        bookmark_folder: Option<&str>,
+        files: &[UploadFile],
+    ) -> Result<UploadResponse> {
+        let boundary = format!(
+            "scraper-client-{:x}",
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos())
+        );
+        let quoted = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut body: Vec<u8> = Vec::new();
+        let mut text_field = |name: &str, value: &str| {
+            body.extend_from_slice(
+                format!(
+                    "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+                )
+                .as_bytes(),
+            );
+        };
+        text_field("session_id", session_id);
+        if let Some(folder) = bookmark_folder {
+            text_field("bookmark_folder", folder);
+        }
+        for f in files {
+            body.extend_from_slice(
+                format!(
+                    "--{boundary}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{}\"\r\n",
+                    quoted(&f.name)
+                )
+                .as_bytes(),
+            );
+            if let Some(ct) = &f.content_type {
+                body.extend_from_slice(format!("Content-Type: {ct}\r\n").as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(&f.bytes);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        let resp = self
+            .http
+            .post(self.url("/api/upload"))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={boundary}"),
+            )
+            .body(body)
+            .send()
+            .await?;
+        json(resp).await
+    }
+
+    /// Indexes a page as the browser rendered it (extensions, bookmarklets).
+    pub async fn capture(&self, req: &CaptureRequest) -> Result<CaptureResponse> {
+        self.post(self.url("/api/capture"), req).await
+    }
+
+    pub async fn connector_confluence(&self, req: &ConfluenceRequest) -> Result<ConnectorResponse> {
+        self.post(self.url("/api/connectors/confluence"), req).await
+    }
+
+    pub async fn connector_notion(&self, req: &NotionRequest) -> Result<ConnectorResponse> {
+        self.post(self.url("/api/connectors/notion"), req).await
+    }
+
+    pub async fn connector_gdrive(&self, req: &GDriveRequest) -> Result<ConnectorResponse> {
+        self.post(self.url("/api/connectors/gdrive"), req).await
+    }
+
+    pub async fn connector_imap(&self, req: &ImapRequest) -> Result<ConnectorResponse> {
+        self.post(self.url("/api/connectors/imap"), req).await
+    }
+
+    // ----- asking and searching -----
+
+    pub async fn ask(&self, req: &AskRequest) -> Result<AskResponse> {
+        self.post(self.url("/api/ask"), req).await
+    }
+
+    /// Like [`Client::ask`], but the answer arrives token by token; see [`AskStream`].
+    pub async fn ask_stream(&self, req: &AskRequest) -> Result<AskStream> {
+        let resp = self
+            .http
+            .post(self.url("/api/ask_stream"))
+            .json(req)
+            .send()
+            .await?;
+        Ok(AskStream {
+            events: EventReader::new(checked(resp).await?),
+        })
+    }
+
+    /// An ask that remembers the conversation; follow-ups are made standalone.
+    pub async fn chat(&self, req: &ChatRequest) -> Result<ChatResponse> {
+        self.post(self.url("/api/chat"), req).await
+    }
+
+    /// Retrieval only, no generation.
+    pub async fn search(&self, req: &SearchRequest) -> Result<SearchResponse> {
+        self.post(self.url("/api/search"), req).await
+    }
+
+    /// Runs a query against `/graphql`; the result has `data` and, if any, `errors`.
+    pub async fn graphql(&self, query: &str, variables: Value) -> Result<Value> {
+        let body = serde_json::json!({ "query": query, "variables": variables });
+        self.post(self.url("/graphql"), &body).await
+    }
+
+    /// The OpenAI-compatible endpoint, answered in one response.
+    pub async fn chat_completion(&self, req: &ChatCompletionRequest) -> Result<Value> {
+        let mut body = serde_json::to_value(req).map_err(Error::Decode)?;
+        body["stream"] = Value::Bool(false);
+        self.post(self.url("/v1/chat/completions"), &body).await
+    }
+
+    /// The OpenAI-compatible endpoint as server-sent events; see [`ChatStream`].
+    pub async fn chat_completion_stream(&self, req: &ChatCompletionRequest) -> Result<ChatStream> {
+        let mut body = serde_json::to_value(req).map_err(Error::Decode)?;
+        body["stream"] = Value::Bool(true);
+        let resp = self
+            .http
+            .post(self.url("/v1/chat/completions"))
+            .json(&body)
+            .send()
+            .await?;
+        Ok(ChatStream {
+            events: EventReader::new(checked(resp).await?),
+        })
+    }
+
+    /// Sessions as OpenAI "models".
+    pub async fn models(&self) -> Result<Value> {
+        self.get(self.url("/v1/models")).await
+    }
+
+    /// The public widget endpoint: answers with `answer` and `sources` only.
+    pub async fn widget_ask(&self, key: &str, question: &str) -> Result<Value> {
+        let body = serde_json::json!({ "key": key, "question": question });
+        self.post(self.url("/api/widget/ask"), &body).await
+    }
+
+    // ----- sessions -----
+
+    pub async fn list_sessions(&self) -> Result<SessionList> {
+        self.get(self.url("/api/sessions")).await
+    }
+
+    /// Creates the session, or updates the configuration of an existing one.
+    pub async fn create_session(&self, req: &CreateSessionRequest) -> Result<Value> {
+        self.post(self.url("/api/sessions"), req).await
+    }
+
+    pub async fn get_session(&self, session_id: &str) -> Result<SessionInfo> {
+        self.get(self.session_url(session_id, "")).await
+    }
+
+    /// Progress of a running indexing job.
+    pub async fn session_status(&self, session_id: &str) -> Result<SessionStatus> {
+        self.get(self.session_url(session_id, "/status")).await
+    }
+
+    /// Writes the session to the server's `--data-dir` now.
+    pub async fn save_session(&self, session_id: &str) -> Result<Value> {
+        self.post(self.session_url(session_id, "/save"), &Value::Null)
+            .await
+    }
+
+    /// The session's `/status` as it changes, until an index build finishes; see
+    /// [`JobEvents`].
+    pub async fn job_events(&self, session_id: &str) -> Result<JobEvents> {
+        let url = self.url(&format!("/api/jobs/{}/events", seg(session_id)));
+        let resp = self.http.get(url).send().await?;
+        Ok(JobEvents {
+            events: EventReader::new(checked(resp).await?),
+        })
+    }
+
+    pub async fn get_crawl_rules(&self, session_id: &str) -> Result<CrawlRules> {
+        self.get(self.session_url(session_id, "/crawl_rules")).await
+    }
+
+    pub async fn put_crawl_rules(&self, session_id: &str, rules: &CrawlRules) -> Result<Value> {
+        self.put(self.session_url(session_id, "/crawl_rules"), rules)
+            .await
+    }
+
+    pub async fn get_glossary(&self, session_id: &str) -> Result<Value> {
+        self.get(self.session_url(session_id, "/glossary")).await
+    }
+
+    pub async fn put_glossary(
+        &self,
+        session_id: &str,
+        glossary: &BTreeMap<String, String>,
+    ) -> Result<Value> {
+        self.put(self.session_url(session_id, "/glossary"), glossary)
+            .await
+    }
+
+    /// Chunks the safety screen flagged.
+    pub async fn flagged(&self, session_id: &str) -> Result<Value> {
+        self.get(self.session_url(session_id, "/flagged")).await
+    }
+
+    pub async fn chunks(&self, session_id: &str, q: &ChunksQuery) -> Result<ChunkList> {
+        self.get_query(self.session_url(session_id, "/chunks"), q)
+            .await
+    }
+
+    pub async fn stats(&self, session_id: &str, top: Option<usize>) -> Result<SessionStats> {
+        let q = [("top", top)];
+        self.get_query(self.session_url(session_id, "/stats"), &q)
+            .await
+    }
+
+    pub async fn coverage(&self, session_id: &str, q: &CoverageQuery) -> Result<Coverage> {
+        self.get_query(self.session_url(session_id, "/coverage"), q)
+            .await
+    }
+
+    pub async fn catalog(&self, session_id: &str, q: &CatalogQuery) -> Result<Value> {
+        self.get_query(self.session_url(session_id, "/catalog"), q)
+            .await
+    }
+
+    /// Re-checks indexed URLs; `prune` drops the chunks of dead pages.
+    pub async fn validate_sources(&self, session_id: &str, prune: bool) -> Result<Value> {
+        let body = serde_json::json!({ "prune": prune });
+        self.post(self.session_url(session_id, "/validate_sources"), &body)
+            .await
+    }
+
+    pub async fn keywords(
+        &self,
+        session_id: &str,
+        top: Option<usize>,
+        per_doc: Option<usize>,
+    ) -> Result<Keywords> {
+        let q = [("top", top), ("per_doc", per_doc)];
+        self.get_query(self.session_url(session_id, "/keywords"), &q)
+            .await
+    }
+
+    pub async fn history(&self, session_id: &str, q: &HistoryQuery) -> Result<HistoryPage> {
+        self.get_query(self.session_url(session_id, "/history"), q)
+            .await
+    }
+
+    /// The history as a downloadable JSON or Markdown document.
+    pub async fn export_history(&self, session_id: &str, format: ExportFormat) -> Result<String> {
+        let format = match format {
+            ExportFormat::Json => "json",
+            ExportFormat::Markdown => "markdown",
+        };
+        let resp = self
+            .http
+            .get(self.session_url(session_id, "/history/export"))
+            .query(&[("format", format)])
+            .send()
+            .await?;
+        Ok(checked(resp).await?.text().await?)
+    }
+
+    // ----- snapshots -----
+
+    pub async fn list_snapshots(&self, session_id: &str) -> Result<Vec<Snapshot>> {
+        self.get(self.session_url(session_id, "/snapshots")).await
+    }
+
+    /// `name` defaults to the current timestamp.
+    pub async fn create_snapshot(&self, session_id: &str, name: Option<&str>) -> Result<Snapshot> {
+        let body = serde_json::json!({ "name": name });
+        self.post(self.session_url(session_id, "/snapshots"), &body)
+            .await
+    }
+
+    pub async fn delete_snapshot(&self, session_id: &str, name: &str) -> Result<()> {
+        let url = self.session_url(session_id, &format!("/snapshots/{}", seg(name)));
+        checked(self.http.delete(url).send().await?).await?;
+        Ok(())
+    }
+
+    pub async fn restore_snapshot(&self, session_id: &str, name: &str) -> Result<RestoreResponse> {
+        let url = self.session_url(session_id, &format!("/snapshots/{}/restore", seg(name)));
+        self.post(url, &serde_json::json!({})).await
+    }
+
+    /// What changed between two snapshots, or a snapshot and the live index (`to: None`).
+    pub async fn diff(
+        &self,
+        session_id: &str,
+        from: &str,
+        to: Option<&str>,
+    ) -> Result<SnapshotDiff> {
+        let q = [("from", Some(from)), ("to", to)];
+        self.get_query(self.session_url(session_id, "/diff"), &q)
+            .await
+    }
+
+    // ----- change monitoring -----
+
+    pub async fn list_watches(&self, session_id: &str) -> Result<Vec<Watch>> {
+        self.get(self.session_url(session_id, "/watches")).await
+    }
+
+    /// The session's watches after adding these.
+    pub async fn add_watches(&self, session_id: &str, req: &WatchRequest) -> Result<Vec<Watch>> {
+        self.post(self.session_url(session_id, "/watches"), req)
+            .await
+    }
+
+    /// The session's remaining watches.
+    pub async fn remove_watches(&self, session_id: &str, urls: &[String]) -> Result<Vec<Watch>> {
+        let resp = self
+            .http
+            .delete(self.session_url(session_id, "/watches"))
+            .json(&serde_json::json!({ "urls": urls }))
+            .send()
+            .await?;
+        json(resp).await
+    }
+
+    pub async fn changes(&self, session_id: &str, limit: Option<usize>) -> Result<Value> {
+        let q = [("limit", limit)];
+        self.get_query(self.session_url(session_id, "/changes"), &q)
+            .await
+    }
+
+    /// The digest settings; `None` once `interval_hours: 0` disabled it.
+    pub async fn set_digest(
+        &self,
+        session_id: &str,
+        req: &DigestRequest,
+    ) -> Result<Option<DigestConfig>> {
+        self.post(self.session_url(session_id, "/digest"), req)
+            .await
+    }
+
+    /// Newest first.
+    pub async fn digests(&self, session_id: &str, limit: Option<usize>) -> Result<Vec<Digest>> {
+        let q = [("limit", limit)];
+        self.get_query(self.session_url(session_id, "/digests"), &q)
+            .await
+    }
+
+    // ----- analysis -----
+
+    /// Entities and relations extracted by the generation model.
+    pub async fn graph(&self, req: &GraphRequest) -> Result<Graph> {
+        self.post(self.url("/api/graph"), req).await
+    }
+
+    /// Largest cluster first.
+    pub async fn cluster(&self, req: &ClusterRequest) -> Result<Vec<Cluster>> {
+        self.post(self.url("/api/cluster"), req).await
+    }
+
+    pub async fn generate_questions(&self, req: &GenerateQuestionsRequest) -> Result<Value> {
+        self.post(self.url("/api/generate_questions"), req).await
+    }
+
+    // ----- administration -----
+
+    /// Re-reads the server's `.env`; the result lists `changed` and
+    /// `restart_required` keys.
+    pub async fn reload_config(&self) -> Result<Value> {
+        self.post(self.url("/api/admin/reload"), &Value::Null).await
+    }
+}
+
+/// ================= Streaming =================
+/// Reads the events of a `text/event-stream` response.
+struct EventReader {
+    resp: reqwest::Response,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl EventReader {
+    fn new(resp: reqwest::Response) -> Self {
+        EventReader {
+            resp,
+            buf: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// The next event's name (`message` when it has none) and data, or `None` at
+    /// the end of the response.
+    async fn next_event(&mut self) -> Result<Option<(String, String)>> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\n\n") {
+                let event: Vec<u8> = self.buf.drain(..end + 2).collect();
+                let event = String::from_utf8_lossy(&event);
+                let mut name = "message";
+                let mut data = Vec::new();
+                for line in event.lines() {
+                    if let Some(v) = line.strip_prefix("event:") {
+                        name = v.trim();
+                    } else if let Some(v) = line.strip_prefix("data:") {
+                        data.push(v.strip_prefix(' ').unwrap_or(v));
+                    }
+                }
+                if data.is_empty() {
+                    continue;
+                }
+                return Ok(Some((name.to_string(), data.join("\n"))));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            match self.resp.chunk().await? {
+                Some(bytes) => {
+                    // CRLF line ends become LF, so events always end in a blank line
+                    self.buf.extend(bytes.iter().filter(|b| **b != b'\r'));
+                }
+                None => {
+                    self.done = true;
+                    if self.buf.iter().all(u8::is_ascii_whitespace) {
+                        return Ok(None);
+                    }
+                    // a last event without its blank line
+                    self.buf.extend_from_slice(b"\n\n");
+                }
+            }
+        }
+    }
+
+    /// Stops reading; later calls return `None`.
+    fn finish(&mut self) {
+        self.done = true;
+        self.buf.clear();
+    }
+}
+
+/// Server-sent events of `/v1/chat/completions`; each event is one
+/// `chat.completion.chunk`.
+pub struct ChatStream {
+    events: EventReader,
+}
+
+impl ChatStream {
+    /// The next chunk, or `None` after `[DONE]` (or the end of the response).
+    pub async fn next_chunk(&mut self) -> Result<Option<Value>> {
+        match self.events.next_event().await? {
+            None => Ok(None),
+            Some((_, data)) if data == "[DONE]" => {
+                self.events.finish();
+                Ok(None)
+            }
+            Some((_, data)) => serde_json::from_str(&data).map(Some).map_err(Error::Decode),
+        }
+    }
+
+    /// Reads the stream to the end and joins the content deltas.
+    pub async fn collect_text(mut self) -> Result<String> {
+        let mut text = String::new();
+        while let Some(chunk) = self.next_chunk().await? {
+            if let Some(delta) = chunk["choices"][0]["delta"]["content"].as_str() {
+                text.push_str(delta);
+            }
+        }
+        Ok(text)
+    }
+}
+
+/// One event of [`AskStream`].
+#[derive(Debug)]
+pub enum AskEvent {
+    /// a piece of the answer as the model writes it
+    Token(String),
+    /// the finished response, the same as `/api/ask` returns; always the last event
+    Answer(Box<AskResponse>),
+}
+
+/// Server-sent events of `/api/ask_stream`.
+pub struct AskStream {
+    events: EventReader,
+}
+
+impl AskStream {
+    /// The next event, or `None` once the answer has been read. An `error` event
+    /// from the server comes back as [`Error::Status`].
+    pub async fn next_event(&mut self) -> Result<Option<AskEvent>> {
+        while let Some((name, data)) = self.events.next_event().await? {
+            match name.as_str() {
+                "token" => {
+                    let token = serde_json::from_str(&data).map_err(Error::Decode)?;
+                    return Ok(Some(AskEvent::Token(token)));
+                }
+                "answer" => {
+                    self.events.finish();
+                    let resp = serde_json::from_str(&data).map_err(Error::Decode)?;
+                    return Ok(Some(AskEvent::Answer(Box::new(resp))));
+                }
+                "error" => {
+                    self.events.finish();
+                    let v: Value = serde_json::from_str(&data).map_err(Error::Decode)?;
+                    return Err(Error::Status {
+                        status: v["status"].as_u64().unwrap_or(500) as u16,
+                        body: v["message"].as_str().unwrap_or_default().to_string(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Skips the tokens and returns the finished response.
+    pub async fn answer(mut self) -> Result<AskResponse> {
+        while let Some(event) = self.next_event().await? {
+            if let AskEvent::Answer(resp) = event {
+                return Ok(*resp);
+            }
+        }
+        Err(Error::Status {
+            status: 502,
+            body: "the stream ended without an answer".into(),
+        })
+    }
+}
+
+/// One event of [`JobEvents`].
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    /// `progress`, or `done` / `failed` as the last event
+    pub kind: String,
+    /// the session's `/status` at that moment
+    pub status: SessionStatus,
+}
+
+/// Server-sent events of `/api/jobs/{id}/events`.
+pub struct JobEvents {
+    events: EventReader,
+}
+
+impl JobEvents {
+    /// The next event, or `None` after the build's `done` or `failed` event.
+    pub async fn next_event(&mut self) -> Result<Option<JobEvent>> {
+        let Some((kind, data)) = self.events.next_event().await? else {
+            return Ok(None);
+        };
+        let status = serde_json::from_str(&data).map_err(Error::Decode)?;
+        Ok(Some(JobEvent { kind, status }))
+    }
+}
@@ -0,0 +1,264 @@
+//! Drives the server binary through `scraper-client`, with the mock model provider
+//! so no Ollama is needed.
+
+mod common;
+
+use common::start_server;
+use scraper_client::{
+    AskEvent, AskRequest, CaptureRequest, ChatCompletionRequest, ChatMessage, ChatRequest,
+    ChunksQuery, CrawlRules, CreateSessionRequest, Error, ExportFormat, HistoryQuery,
+    SearchRequest, UploadFile,
+};
+
+const PAGE: &str = "<html><head><title>Admissions</title></head><body><main>\
+    <h1>Master Data Science</h1>\
+    <p>The application deadline for the winter semester is 15 July 2025.</p>\
+    <p>Applicants from China, Vietnam and India need an APS certificate.</p>\
+    </main></body></html>";
+
+#[tokio::test]
+async fn client_covers_the_main_flows() {
+    let server = start_server().await;
+    let client = &server.client;
+
+    client
+        .create_session(&CreateSessionRequest {
+            session_id: Some("sdk test".into()),
+            crawl_rules: Some(CrawlRules {
+                allow: vec![],
+                deny: vec!["/print/".into()],
+            }),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        client.get_crawl_rules("sdk test").await.unwrap().deny,
+        vec!["/print/".to_string()]
+    );
+
+    client
+        .capture(&CaptureRequest {
+            session_id: "sdk test".into(),
+            url: "https://example.edu/admissions".into(),
+            html: PAGE.into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let mut events = client.job_events("sdk test").await.unwrap();
+    let uploaded = client
+        .upload(
+            "sdk test",
+            &[UploadFile {
+                name: "fees.txt".into(),
+                bytes: "Tuition fees are 1500 EUR per semester.".into(),
+                content_type: None,
+            }],
+        )
+        .await
+        .unwrap();
+    assert_eq!(uploaded.files_processed, 1);
+    assert_eq!(uploaded.files[0].file, "fees.txt");
+    assert_eq!(uploaded.files[0].status, "extracted");
+    let last = loop {
+        let event = events.next_event().await.unwrap().expect("a final event");
+        if event.kind != "progress" {
+            break event;
+        }
+    };
+    assert_eq!(last.kind, "done");
+    assert!(last.status.chunks >= 2);
+    assert_eq!(last.status.progress.expect("progress").phase, "done");
+    assert!(events.next_event().await.unwrap().is_none());
+
+    let sessions = client.list_sessions().await.unwrap();
+    let summary = sessions
+        .sessions
+        .iter()
+        .find(|s| s.session_id == "sdk test")
+        .expect("session listed");
+    assert!(summary.chunks >= 2);
+
+    let chunks = client
+        .chunks(
+            "sdk test",
+            &ChunksQuery {
+                url: Some("https://example.edu/admissions".into()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert!(chunks.total >= 1);
+    assert!(chunks.items[0].text.contains("15 July 2025"));
+    assert!(chunks.items[0].values.contains(&"date:07-15".to_string()));
+
+    let hits = client
+        .search(&SearchRequest {
+            session_id: "sdk test".into(),
+            query: "APS certificate".into(),
+            top_k: Some(3),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(hits.results[0].url, "https://example.edu/admissions");
+
+    let resp = client
+        .ask(&AskRequest::new(
+            "sdk test",
+            "Who needs an APS certificate?",
+        ))
+        .await
+        .unwrap();
+    assert!(!resp.answer.is_empty());
+    assert!(resp
+        .sources
+        .contains(&"https://example.edu/admissions".to_string()));
+    assert_eq!(resp.citations.len(), resp.sources.len());
+
+    let history = client
+        .history("sdk test", &HistoryQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(history.total, 1);
+    assert_eq!(history.items[0].question, "Who needs an APS certificate?");
+    let md = client
+        .export_history("sdk test", ExportFormat::Markdown)
+        .await
+        .unwrap();
+    assert!(md.contains("Who needs an APS certificate?"));
+
+    let gql = client
+        .graphql(
+            "query ($id: ID!) { session(id: $id) { documents { totalCount } } }",
+            serde_json::json!({ "id": "sdk test" }),
+        )
+        .await
+        .unwrap();
+    assert_eq!(gql["data"]["session"]["documents"]["totalCount"], 2);
+
+    let snap = client
+        .create_snapshot("sdk test", Some("v1"))
+        .await
+        .unwrap();
+    assert_eq!(snap.name, "v1");
+    assert_eq!(snap.chunks, summary.chunks);
+    let diff = client.diff("sdk test", "v1", None).await.unwrap();
+    assert_eq!(diff.to, "current");
+    assert!(diff.urls_added.is_empty());
+    client.delete_snapshot("sdk test", "v1").await.unwrap();
+    assert!(client.list_snapshots("sdk test").await.unwrap().is_empty());
+
+    let info = client.get_session("sdk test").await.unwrap();
+    assert_eq!(info.pages_indexed, 2);
+    assert!(info.memory.total > 0);
+    let stats = client.stats("sdk test", Some(5)).await.unwrap();
+    assert_eq!(stats.documents, 2);
+    assert!(stats.df_head.len() <= 5);
+}
+
+#[tokio::test]
+async fn chat_completions_stream_and_errors() {
+    let server = start_server().await;
+    let client = &server.client;
+    client
+        .capture(&CaptureRequest {
+            session_id: "chat".into(),
+            url: "https://example.edu/admissions".into(),
+            html: PAGE.into(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    let req = ChatCompletionRequest {
+        model: "chat".into(),
+        messages: vec![ChatMessage {
+            role: "user".into(),
+            content: "When is the application deadline?".into(),
+        }],
+        temperature: None,
+    };
+    let whole = client.chat_completion(&req).await.unwrap();
+    let expected = whole["choices"][0]["message"]["content"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let streamed = client
+        .chat_completion_stream(&req)
+        .await
+        .unwrap()
+        .collect_text()
+        .await
+        .unwrap();
+    assert_eq!(streamed, expected);
+
+    let mut events = client
+        .ask_stream(&AskRequest::new(
+            "chat",
+            "When is the application deadline?",
+        ))
+        .await
+        .unwrap();
+    let mut tokens = String::new();
+    let answer = loop {
+        match events.next_event().await.unwrap() {
+            Some(AskEvent::Token(t)) => tokens.push_str(&t),
+            Some(AskEvent::Answer(resp)) => break resp,
+            None => panic!("stream ended without an answer"),
+        }
+    };
+    assert!(!tokens.is_empty());
+    assert!(answer.answer.starts_with(tokens.trim_end()));
+    assert!(events.next_event().await.unwrap().is_none());
+
+    let first = client
+        .chat(&ChatRequest {
+            conversation_id: None,
+            ask: AskRequest::new("chat", "When is the application deadline?"),
+        })
+        .await
+        .unwrap();
+    assert_eq!(first.standalone_question, None);
+    assert!(!first.answer.answer.starts_with("Following up on:"));
+    // the mock model resolves "it" to the previous question's subject, and opens
+    // its answer with the last earlier question it was given
+    let follow_up = client
+        .chat(&ChatRequest {
+            conversation_id: Some(first.conversation_id.clone()),
+            ask: AskRequest::new("chat", "Is it the same for international applicants?"),
+        })
+        .await
+        .unwrap();
+    assert_eq!(follow_up.conversation_id, first.conversation_id);
+    assert_eq!(
+        follow_up.standalone_question.as_deref(),
+        Some("Is the application deadline the same for international applicants?")
+    );
+    assert!(
+        follow_up
+            .answer
+            .answer
+            .starts_with("Following up on: When is the application deadline?"),
+        "{}",
+        follow_up.answer.answer
+    );
+
+    match client
+        .ask_stream(&AskRequest::new("no such session", "x"))
+        .await
+        .unwrap()
+        .answer()
+        .await
+    {
+        Err(Error::Status { status, .. }) => assert_eq!(status, 400),
+        other => panic!("expected a 400, got {other:?}"),
+    }
+
+    match client.get_session("no such session").await {
+        Err(Error::Status { status, .. }) => assert_eq!(status, 404),
+        other => panic!("expected a 404, got {other:?}"),
+    }
+}
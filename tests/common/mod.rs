@@ -0,0 +1,43 @@
+//! Starts the server binary with the mock model provider, so no Ollama is needed.
+
+use scraper_client::Client;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+pub struct Server {
+    child: Child,
+    pub client: Client,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub async fn start_server() -> Server {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let child = Command::new(env!("CARGO_BIN_EXE_ollama_site_qa_web"))
+        .args(["--bind", &format!("127.0.0.1:{port}")])
+        .env("PROVIDER", "mock")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("server starts");
+    let server = Server {
+        child,
+        client: Client::new(format!("http://127.0.0.1:{port}")),
+    };
+    for _ in 0..100 {
+        if server.client.list_sessions().await.is_ok() {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server did not come up on port {port}");
+}
@@ -0,0 +1,79 @@
+//! Indexes the small corpus in `tests/golden/corpus` with the mock provider and checks
+//! that known questions retrieve the expected files first. A ranking change that moves
+//! one of them shows up here before it shows up in answers.
+
+mod common;
+
+use common::start_server;
+use scraper_client::{AskRequest, CreateSessionRequest, UploadFile};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Deserialize)]
+struct Golden {
+    question: String,
+    /// the files the first sources must come from, best first
+    top: Vec<String>,
+}
+
+#[tokio::test]
+async fn golden_questions_retrieve_the_expected_files() {
+    let server = start_server().await;
+    let client = &server.client;
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut files: Vec<UploadFile> = std::fs::read_dir(dir.join("corpus"))
+        .unwrap()
+        .map(|e| {
+            let path = e.unwrap().path();
+            UploadFile {
+                name: path.file_name().unwrap().to_string_lossy().into_owned(),
+                bytes: std::fs::read(&path).unwrap(),
+                content_type: None,
+            }
+        })
+        .collect();
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    client
+        .create_session(&CreateSessionRequest {
+            session_id: Some("golden".into()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let mut events = client.job_events("golden").await.unwrap();
+    client.upload("golden", &files).await.unwrap();
+    let last = loop {
+        let event = events.next_event().await.unwrap().expect("a final event");
+        if event.kind != "progress" {
+            break event;
+        }
+    };
+    assert_eq!(last.kind, "done", "{:?}", last.status);
+
+    let golden: Vec<Golden> =
+        serde_json::from_slice(&std::fs::read(dir.join("questions.json")).unwrap()).unwrap();
+    for g in golden {
+        let resp = client
+            .ask(&AskRequest {
+                session_id: "golden".into(),
+                question: g.question.clone(),
+                top_k: Some(3),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let got: Vec<&str> = resp
+            .sources
+            .iter()
+            .map(|s| s.rsplit('/').next().unwrap_or(s))
+            .collect();
+        assert!(
+            got.starts_with(&g.top.iter().map(String::as_str).collect::<Vec<_>>()),
+            "{}: expected {:?} first, got {:?}",
+            g.question,
+            g.top,
+            resp.sources
+        );
+    }
+}
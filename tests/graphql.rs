@@ -0,0 +1,101 @@
+//! The hand-written `/graphql` parser against malformed and hostile queries: each
+//! must come back as a GraphQL error, and the server must keep answering.
+
+mod common;
+
+use common::start_server;
+use scraper_client::Error;
+use serde_json::{json, Value};
+
+fn error_message(resp: &Value) -> &str {
+    resp["errors"][0]["message"]
+        .as_str()
+        .unwrap_or_else(|| panic!("no error in {resp}"))
+}
+
+#[tokio::test]
+async fn graphql_rejects_malformed_and_deeply_nested_queries() {
+    let server = start_server().await;
+    let client = &server.client;
+
+    let malformed = [
+        ("", "Expected \"{\""),
+        ("{", "Expected a name"),
+        ("{ sessions { nodes { id }", "Expected a name"),
+        ("{ sessions(first: ) { totalCount } }", "Expected a value"),
+        ("{ sessions(first: 1e) { totalCount } }", "Bad number"),
+        ("{ session(id: \"unterminated) { id } }", "string"),
+        ("{ session(id: \"\\u12\") { id } }", "escape"),
+        ("{ session(id: $id) { id } }", "not defined"),
+        ("mutation { sessions { totalCount } }", "Only queries"),
+        ("{ ...frag }", "Fragments"),
+        ("{ sessions @skip(if: true) { totalCount } }", "Directives"),
+        (
+            "{ sessions { totalCount } } { sessions { totalCount } }",
+            "one operation",
+        ),
+        ("{ }", "Empty selection set"),
+        ("{ sessions { totalCount } } #", ""),
+        ("{ sessions ^ }", "Unexpected character"),
+    ];
+    for (query, expected) in malformed {
+        let resp = client.graphql(query, json!({})).await.unwrap();
+        if expected.is_empty() {
+            assert!(resp["errors"].is_null(), "{query:?}: {resp}");
+        } else {
+            assert!(
+                error_message(&resp).contains(expected),
+                "{query:?}: expected {expected:?}, got {resp}"
+            );
+        }
+    }
+
+    // compact, so they stay under the body limit and reach the parser
+    let deep_selection = format!("{{{}{}", "a{".repeat(20_000), "}".repeat(20_001));
+    let deep_list = format!(
+        "{{session(id:{}1{}){{id}}}}",
+        "[".repeat(25_000),
+        "]".repeat(25_000)
+    );
+    let deep_object = format!(
+        "{{search(sessionId:\"x\",query:\"q\",filters:{}1{}){{matchedChunks}}}}",
+        "{a:".repeat(10_000),
+        "}".repeat(10_000)
+    );
+    let deep_type = format!(
+        "query($id:{}ID{}){{session(id:$id){{id}}}}",
+        "[".repeat(25_000),
+        "]".repeat(25_000)
+    );
+    for query in [deep_selection, deep_list, deep_object, deep_type] {
+        let resp = client.graphql(&query, json!({})).await.unwrap();
+        assert!(
+            error_message(&resp).contains("nested deeper than 32 levels"),
+            "{resp}"
+        );
+    }
+
+    // 31 levels of list values are still fine (the argument itself is the 32nd)
+    let within = format!(
+        "{{ session(id: {}\"x\"{}) {{ id }} }}",
+        "[".repeat(30),
+        "]".repeat(30)
+    );
+    let resp = client.graphql(&within, json!({})).await.unwrap();
+    assert!(!error_message(&resp).contains("nested"), "{resp}");
+
+    let huge = format!(
+        "{{ sessions {{ {} totalCount }} }}",
+        "totalCount ".repeat(20_000)
+    );
+    match client.graphql(&huge, json!({})).await {
+        Err(Error::Status { status, .. }) => assert_eq!(status, 413),
+        other => panic!("expected a 413, got {other:?}"),
+    }
+
+    let resp = client
+        .graphql("{ sessions { totalCount } }", json!({}))
+        .await
+        .unwrap();
+    assert_eq!(resp["data"]["sessions"]["totalCount"], 0, "{resp}");
+}